@@ -1,67 +1,404 @@
 use std::error::Error;
+use std::fmt;
 use wapc::{ModuleState, WapcFunctions, WebAssemblyEngineProvider, HOST_NAMESPACE};
-use wasmtime::{Engine, Extern, ExternType, Func, Instance, Module, Store};
+use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::WasiCtx;
+use wasmtime::{
+    Caller, Config, Engine, ExternType, Func, Instance, InstancePre, Linker, Module, Store, Trap,
+    TrapCode,
+};
 
+use std::sync::mpsc;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate log;
 
 mod callbacks;
 
-macro_rules! call {
-    ($func:expr, $($p:expr),*) => {
-      match $func.call(&[$($p.into()),*]) {
-        Ok(result) => {
-          let result: i32 = result[0].i32().unwrap();
-          result
+/// Import module name used by WASI guests, analogous to waPC's `HOST_NAMESPACE`.
+const WASI_NAMESPACE: &str = "wasi_snapshot_preview1";
+
+/// Errors specific to invoking a guest export through [`WasmtimeEngineProvider`],
+/// as opposed to ordinary host setup failures that are reported as `Box<dyn Error>`.
+#[derive(Debug)]
+pub enum GuestCallError {
+    /// The call exhausted its fuel budget before returning. See [`WasmtimeEngineProviderBuilder::with_fuel`].
+    OutOfFuel,
+    /// The call ran longer than its wall-clock budget. See [`WasmtimeEngineProviderBuilder::with_timeout`].
+    Timeout,
+    /// The guest trapped for any other reason.
+    Trap(Trap),
+}
+
+impl fmt::Display for GuestCallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestCallError::OutOfFuel => write!(f, "guest call exhausted its fuel budget"),
+            GuestCallError::Timeout => write!(f, "guest call exceeded its timeout"),
+            GuestCallError::Trap(trap) => write!(f, "guest call trapped: {}", trap),
         }
-        Err(e) => {
-            error!("Failure invoking guest module handler: {:?}", e);
-            0
+    }
+}
+
+impl Error for GuestCallError {}
+
+/// Observes the guest/host boundary of every host function a guest calls
+/// (`__host_call` and the other waPC callbacks, plus WASI functions when
+/// enabled), installed via `Store::call_hook`. Useful for metrics and tracing
+/// around how often the guest calls into the host and how long it blocks there.
+pub trait CallObserver: Send + Sync {
+    /// Called right before the guest enters a host function. Returning `Err`
+    /// aborts the host call, which Wasmtime surfaces to the guest as a trap.
+    fn entering_host(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called right after the guest returns from a host function.
+    fn leaving_host(&self) {}
+}
+
+/// Data carried by the `Store` created for a single `call`. It outlives any
+/// one `Store`: `call` moves it in via `Store::new` and moves it back out via
+/// `Store::into_data` once the call returns, so its `host`/`wasi` persist
+/// across calls even though no `Store` does.
+struct StoreState {
+    host: Arc<ModuleState>,
+    wasi: Option<WasiCtx>,
+}
+
+macro_rules! call {
+    ($store:expr, $func:expr, $($p:expr),*) => {{
+        let mut results = [wasmtime::Val::I32(0)];
+        match $func.call(&mut $store, &[$($p.into()),*], &mut results) {
+            Ok(()) => {
+                let result: i32 = results[0].i32().unwrap();
+                Ok(result)
+            }
+            Err(trap) => {
+                match trap.trap_code() {
+                    Some(TrapCode::OutOfFuel) => {
+                        error!("Guest module exhausted its fuel budget");
+                        Err(GuestCallError::OutOfFuel)
+                    }
+                    Some(TrapCode::Interrupt) => {
+                        error!("Guest module exceeded its timeout");
+                        Err(GuestCallError::Timeout)
+                    }
+                    _ => {
+                        error!("Failure invoking guest module handler: {:?}", trap);
+                        Err(GuestCallError::Trap(trap))
+                    }
+                }
+            }
         }
-      }
+    }}
+}
+
+/// Builds a [`WasmtimeEngineProvider`] with optional execution limits.
+#[derive(Default)]
+pub struct WasmtimeEngineProviderBuilder {
+    config: Option<Config>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    wasi: Option<WasiCtxBuilder>,
+    observer: Option<Arc<dyn CallObserver>>,
+}
+
+impl WasmtimeEngineProviderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Supplies a base `Config` for the `Engine`, e.g. to tune the Cranelift
+    /// optimization level, enable the module cache, or pick static vs dynamic
+    /// memories. `with_fuel`/`with_timeout` still toggle `consume_fuel`/
+    /// `epoch_interruption` on top of whatever `config` already has set.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Caps the amount of fuel available to a single `__guest_call` invocation.
+    /// When the budget is exhausted the call fails with [`GuestCallError::OutOfFuel`]
+    /// instead of running forever.
+    pub fn with_fuel(mut self, budget: u64) -> Self {
+        self.fuel = Some(budget);
+        self
+    }
+
+    /// Caps the wall-clock duration of a single `__guest_call` invocation.
+    /// When the deadline elapses the call fails with [`GuestCallError::Timeout`]
+    /// instead of running forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Lets the guest import `wasi_snapshot_preview1`, wiring stdio, preopened
+    /// dirs, env vars and args through to the host as configured on `wasi`.
+    pub fn enable_wasi(mut self, wasi: WasiCtxBuilder) -> Self {
+        self.wasi = Some(wasi);
+        self
+    }
+
+    /// Registers a [`CallObserver`] that is notified around every guest/host
+    /// boundary crossing, i.e. every host function the guest calls.
+    pub fn with_call_observer(mut self, observer: Arc<dyn CallObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn build(self, buf: &[u8]) -> Result<WasmtimeEngineProvider, Box<dyn Error>> {
+        let wasi_ctx = self.wasi.map(|builder| builder.build()).transpose()?;
+        WasmtimeEngineProvider::new_with_limits(
+            buf,
+            self.config,
+            self.fuel,
+            self.timeout,
+            wasi_ctx,
+            self.observer,
+        )
     }
 }
 
-/// A waPC engine provider that encapsulates the Wasmtime WebAssembly runtime
-#[derive(Clone)]
+/// A waPC engine provider that encapsulates the Wasmtime WebAssembly runtime.
+///
+/// `engine`/`linker` live for the provider's whole lifetime, and `module` (with
+/// its `instance_pre`) until the next `replace`; no `Store` outlives a call.
+/// Every `call` instantiates into a throwaway `Store` (see [`StoreState`]), so
+/// an unbounded number of calls never accumulates unbounded instances/memories
+/// or leftover fuel in a single long-lived `Store`.
 pub struct WasmtimeEngineProvider {
-    host: Option<Arc<ModuleState>>,
+    // Kept around (rather than reconstructed per module) so that the timeout
+    // timer thread can call `increment_epoch` on the very engine the guest
+    // is running against, and so `replace` can recompile into the same engine.
+    engine: Engine,
     module: Module,
+    // Host callbacks (and WASI, if enabled) are registered once, independent
+    // of any particular `Store`: only `instance_pre` needs to be rebuilt when
+    // the module is hot-swapped.
+    linker: Arc<Linker<StoreState>>,
+    instance_pre: Option<Arc<InstancePre<StoreState>>>,
+    wasi_enabled: bool,
+    pending_wasi: Option<WasiCtx>,
+    state: Option<StoreState>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    observer: Option<Arc<dyn CallObserver>>,
 }
 
 impl WasmtimeEngineProvider {
     pub fn new(buf: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Self::new_with_limits(buf, None, None, None, None, None)
+    }
+
+    /// Like `new`, but lets the embedder choose the `Engine`'s `Config` (Cranelift
+    /// optimization level, module cache, static vs dynamic memories, ...) instead
+    /// of compiling `buf` against `Config::default()`.
+    pub fn new_with_config(buf: &[u8], config: Config) -> Result<Self, Box<dyn Error>> {
+        Self::new_with_limits(buf, Some(config), None, None, None, None)
+    }
+
+    /// Loads a module that was already AOT-compiled by `Engine::precompile_module`
+    /// (or produced by [`WasmtimeEngineProvider::serialize`]), skipping JIT
+    /// compilation of `bytes` entirely. `config` must be compatible with the one
+    /// the bytes were compiled with, including `consume_fuel`/`epoch_interruption`
+    /// if `fuel`/`timeout` were set when the module was precompiled: pass the
+    /// same budgets here so the `Store` each `call` creates actually has fuel to
+    /// spend instead of trapping `OutOfFuel` on its first instruction.
+    ///
+    /// # Safety
+    /// `bytes` must be trusted: they are loaded as-is via `Module::deserialize`,
+    /// which performs no validation that they were produced by a compatible
+    /// Wasmtime build.
+    pub unsafe fn from_precompiled(
+        bytes: &[u8],
+        config: Config,
+        fuel: Option<u64>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let engine = Self::build_engine(Some(config), fuel.is_some(), timeout.is_some())?;
+        let module = Module::deserialize(&engine, bytes)?;
+        let linker = Self::build_linker(&engine, false)?;
         Ok(WasmtimeEngineProvider {
-            host: None,
-            module: Self::load_module(buf)?,
+            engine,
+            module,
+            linker: Arc::new(linker),
+            instance_pre: None,
+            wasi_enabled: false,
+            pending_wasi: None,
+            state: None,
+            fuel,
+            timeout,
+            observer: None,
         })
     }
 
-    fn load_module(buf: &[u8]) -> Result<Module, Box<dyn Error>> {
-        let engine = Engine::default();
-        Ok(Module::new(&engine, buf)?)
+    /// Serializes the loaded module so it can later be restored without JIT
+    /// compilation via [`WasmtimeEngineProvider::from_precompiled`].
+    pub fn serialize(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.module.serialize()?)
+    }
+
+    fn new_with_limits(
+        buf: &[u8],
+        config: Option<Config>,
+        fuel: Option<u64>,
+        timeout: Option<Duration>,
+        wasi_ctx: Option<WasiCtx>,
+        observer: Option<Arc<dyn CallObserver>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let engine = Self::build_engine(config, fuel.is_some(), timeout.is_some())?;
+        let module = Self::load_module(&engine, buf)?;
+        let wasi_enabled = wasi_ctx.is_some();
+        let linker = Self::build_linker(&engine, wasi_enabled)?;
+        Ok(WasmtimeEngineProvider {
+            engine,
+            module,
+            linker: Arc::new(linker),
+            instance_pre: None,
+            wasi_enabled,
+            pending_wasi: wasi_ctx,
+            state: None,
+            fuel,
+            timeout,
+            observer,
+        })
+    }
+
+    fn build_engine(
+        base_config: Option<Config>,
+        consume_fuel: bool,
+        epoch_interruption: bool,
+    ) -> Result<Engine, Box<dyn Error>> {
+        let mut config = base_config.unwrap_or_else(Config::new);
+        // Only ever force these flags on: a caller who already enabled
+        // consume_fuel/epoch_interruption on their own `Config` (without going
+        // through `with_fuel`/`with_timeout`) must not have that silently
+        // disabled here.
+        if consume_fuel {
+            config.consume_fuel(true);
+        }
+        if epoch_interruption {
+            config.epoch_interruption(true);
+        }
+        Ok(Engine::new(&config)?)
+    }
+
+    fn load_module(engine: &Engine, buf: &[u8]) -> Result<Module, Box<dyn Error>> {
+        Ok(Module::new(engine, buf)?)
+    }
+
+    /// Registers every waPC host callback (and, if `wasi_enabled`, the WASI
+    /// preview1 ABI) once per provider. Registration only needs `&Engine`,
+    /// not a `Store`, so the resulting `Linker` is reusable across every call
+    /// and every hot-swapped module.
+    fn build_linker(
+        engine: &Engine,
+        wasi_enabled: bool,
+    ) -> Result<Linker<StoreState>, Box<dyn Error>> {
+        let mut linker = Linker::new(engine);
+        register_host_functions(&mut linker)?;
+        if wasi_enabled {
+            wasmtime_wasi::add_to_linker(&mut linker, |state: &mut StoreState| {
+                state
+                    .wasi
+                    .as_mut()
+                    .expect("wasi state is set whenever WASI support is enabled")
+            })?;
+        }
+        Ok(linker)
+    }
+
+    /// Resolves every import of `self.module` exactly once and produces a
+    /// pre-linked `InstancePre`, so that a `call` only has to pay for
+    /// instantiation rather than a full re-link.
+    fn build_instance_pre(&self) -> Result<InstancePre<StoreState>, Box<dyn Error>> {
+        validate_imports(&self.module, self.wasi_enabled)?;
+        Ok(self.linker.instantiate_pre(&self.module)?)
     }
 }
 
 impl WebAssemblyEngineProvider for WasmtimeEngineProvider {
     fn init(&mut self, host: Arc<ModuleState>) -> Result<(), Box<dyn Error>> {
-        self.host = Some(host);
+        self.state = Some(StoreState {
+            host,
+            wasi: self.pending_wasi.take(),
+        });
+        self.instance_pre = Some(Arc::new(self.build_instance_pre()?));
         Ok(())
     }
 
     fn call(&mut self, op_length: i32, msg_length: i32) -> Result<i32, Box<dyn Error>> {
         debug_assert!(self.initialized());
-        let instance = self.instantiate()?;
-        let guest_call_fn = guest_call_fn(&instance)?;
+        let state = self
+            .state
+            .take()
+            .expect("state is populated by init() before any call()");
+        let mut store = Store::new(&self.engine, state);
+
+        let result = self.run_call(&mut store, op_length, msg_length);
+
+        // Restore the host/WASI state regardless of whether `run_call` above
+        // succeeded: an instantiation failure or a trap in the guest's start
+        // function must not leave `self.state` empty, or the *next* call would
+        // panic on the `.expect` above instead of simply returning this error.
+        self.state = Some(store.into_data());
+
+        result
+    }
+
+    fn run_call(
+        &self,
+        store: &mut Store<StoreState>,
+        op_length: i32,
+        msg_length: i32,
+    ) -> Result<i32, Box<dyn Error>> {
+        if let Some(budget) = self.fuel {
+            store.add_fuel(budget)?;
+        }
+
+        let timer = self.timeout.map(|timeout| {
+            store.set_epoch_deadline(1);
+            TimeoutTimer::spawn(self.engine.clone(), timeout)
+        });
+
+        if let Some(observer) = self.observer.clone() {
+            store.call_hook(move |_ctx, kind| match kind {
+                wasmtime::CallHook::CallingHost => observer.entering_host().map_err(Trap::new),
+                wasmtime::CallHook::ReturningFromHost => {
+                    observer.leaving_host();
+                    Ok(())
+                }
+                _ => Ok(()),
+            });
+        }
+
+        let instance_pre = self
+            .instance_pre
+            .as_ref()
+            .expect("instance_pre is populated by init() before any call()")
+            .clone();
+        let instance = instance_pre.instantiate(&mut *store)?;
+        initialize(store, &instance)?;
+        let guest_call_fn = guest_call_fn(store, &instance)?;
 
         // Note that during this call, the guest should, through the functions
         // it imports from the host, set the guest error and response
+        let callresult = call!(*store, guest_call_fn, op_length, msg_length);
 
-        let callresult: i32 = call!(guest_call_fn, op_length, msg_length);
+        // Cancel (and join) the timer before the deadline can fire: otherwise
+        // a timer from this already-finished call could increment the epoch
+        // while a *later* call is in flight and trip that call's deadline
+        // instead of this one's.
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
 
-        Ok(callresult)
+        Ok(callresult?)
     }
 
     fn replace(&mut self, buf: &[u8]) -> Result<(), Box<dyn Error>> {
@@ -71,95 +408,235 @@ impl WebAssemblyEngineProvider for WasmtimeEngineProvider {
             buf.len()
         );
 
-        self.module = Self::load_module(buf)?;
+        // `self.linker` stays valid across a hot swap: it only depends on
+        // `self.engine`, not on `self.module`.
+        self.module = Self::load_module(&self.engine, buf)?;
+        self.instance_pre = Some(Arc::new(self.build_instance_pre()?));
         Ok(())
     }
 }
 
 impl WasmtimeEngineProvider {
     fn initialized(&self) -> bool {
-        self.host.is_some()
+        self.state.is_some()
     }
+}
 
-    fn instantiate(&self) -> Result<Instance, Box<dyn Error>> {
-        debug_assert!(self.initialized());
-        let host = self.host.as_ref().unwrap().clone();
-        let engine = self.module.engine();
-        let store = Store::new(engine);
-        let imports = arrange_imports(&self.module, host, store.clone());
-        let instance = wasmtime::Instance::new(&store, &self.module, imports?.as_slice())?;
-        initialize(&instance)?;
-        Ok(instance)
+/// A one-shot wall-clock timer for epoch-based interruption. Must be
+/// `cancel()`-ed once the call it was spawned for returns, so a stray
+/// `increment_epoch()` can never land after that point and affect a
+/// subsequent call's deadline.
+struct TimeoutTimer {
+    cancel: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl TimeoutTimer {
+    fn spawn(engine: Engine, timeout: Duration) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            // `Timeout` here means the channel timed out without a cancellation,
+            // i.e. the deadline genuinely elapsed. A dropped timer disconnects
+            // the channel instead, which does not count as an elapsed deadline.
+            if let Err(mpsc::RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) {
+                engine.increment_epoch();
+            }
+        });
+        TimeoutTimer { cancel, handle }
+    }
+
+    fn cancel(self) {
+        let _ = self.cancel.send(());
+        let _ = self.handle.join();
     }
 }
 
-fn initialize(instance: &Instance) -> Result<(), Box<dyn Error>> {
+fn initialize(store: &mut Store<StoreState>, instance: &Instance) -> Result<(), Box<dyn Error>> {
     for starter in wapc::WapcFunctions::REQUIRED_STARTS.iter() {
-        if let Some(ext) = instance.get_export(starter) {
-            ext.into_func().unwrap().call(&[])?;
+        if let Some(func) = instance.get_func(&mut *store, starter) {
+            func.call(&mut *store, &[], &mut [])?;
         }
     }
     Ok(())
 }
 
-/// wasmtime requires that the list of callbacks be "zippable" with the list
-/// of module imports. In order to ensure that both lists are in the same
-/// order, we have to loop through the module imports and instantiate the
-/// corresponding callback. We **cannot** rely on a predictable import order
-/// in the wasm module
-fn arrange_imports(
-    module: &Module,
-    host: Arc<ModuleState>,
-    store: Store,
-) -> Result<Vec<Extern>, Box<dyn Error>> {
-    Ok(module
-        .imports()
-        .filter_map(|imp| {
-            if let ExternType::Func(_) = imp.ty() {
-                match imp.module() {
-                    HOST_NAMESPACE => {
-                        Some(callback_for_import(imp.name(), host.clone(), store.clone()))
-                    }
-                    other => panic!("import module `{}` was not found", other), //TODO: get rid of panic
+/// wasmtime requires that every import be resolved before instantiation.
+/// `self.linker` unconditionally carries every waPC host callback, but the
+/// WASI preview1 ABI is only registered when `enable_wasi` was actually
+/// called. This checks the module name of every function import: imports
+/// from `HOST_NAMESPACE`, and from `wasi_snapshot_preview1` when WASI is
+/// enabled, pass; any other module is reported as not found. Unknown names
+/// inside an accepted module and non-function imports are not checked here;
+/// `Linker::instantiate_pre` rejects whatever the linker does not define.
+fn validate_imports(module: &Module, wasi_enabled: bool) -> Result<(), Box<dyn Error>> {
+    for imp in module.imports() {
+        if let ExternType::Func(_) = imp.ty() {
+            match imp.module() {
+                HOST_NAMESPACE => {}
+                WASI_NAMESPACE if wasi_enabled => {}
+                other => {
+                    return Err(format!("import module `{}` was not found", other).into());
                 }
-            } else {
-                None
             }
-        })
-        .collect())
-}
-
-fn callback_for_import(import: &str, host: Arc<ModuleState>, store: Store) -> Extern {
-    match import {
-        WapcFunctions::HOST_CONSOLE_LOG => callbacks::console_log_func(&store, host.clone()).into(),
-        WapcFunctions::HOST_CALL => callbacks::host_call_func(&store, host.clone()).into(),
-        WapcFunctions::GUEST_REQUEST_FN => {
-            callbacks::guest_request_func(&store, host.clone()).into()
-        }
-        WapcFunctions::HOST_RESPONSE_FN => {
-            callbacks::host_response_func(&store, host.clone()).into()
-        }
-        WapcFunctions::HOST_RESPONSE_LEN_FN => {
-            callbacks::host_response_len_func(&store, host.clone()).into()
-        }
-        WapcFunctions::GUEST_RESPONSE_FN => {
-            callbacks::guest_response_func(&store, host.clone()).into()
-        }
-        WapcFunctions::GUEST_ERROR_FN => callbacks::guest_error_func(&store, host.clone()).into(),
-        WapcFunctions::HOST_ERROR_FN => callbacks::host_error_func(&store, host.clone()).into(),
-        WapcFunctions::HOST_ERROR_LEN_FN => {
-            callbacks::host_error_len_func(&store, host.clone()).into()
         }
-        _ => unreachable!(),
     }
+    Ok(())
+}
+
+/// Registers the nine waPC host callbacks into `linker`, independent of any
+/// particular `Store`: each closure pulls the `Arc<ModuleState>` it needs out
+/// of `Caller::data` at call time rather than capturing it up front.
+fn register_host_functions(linker: &mut Linker<StoreState>) -> Result<(), Box<dyn Error>> {
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_CONSOLE_LOG,
+        |caller: Caller<'_, StoreState>, ptr: i32, len: i32| {
+            callbacks::console_log_func(caller, ptr, len)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_CALL,
+        |caller: Caller<'_, StoreState>,
+         bd_ptr: i32,
+         bd_len: i32,
+         ns_ptr: i32,
+         ns_len: i32,
+         op_ptr: i32,
+         op_len: i32,
+         ptr: i32,
+         len: i32|
+         -> i32 {
+            callbacks::host_call_func(
+                caller, bd_ptr, bd_len, ns_ptr, ns_len, op_ptr, op_len, ptr, len,
+            )
+        },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::GUEST_REQUEST_FN,
+        |caller: Caller<'_, StoreState>, op_ptr: i32, ptr: i32| {
+            callbacks::guest_request_func(caller, op_ptr, ptr)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_RESPONSE_FN,
+        |caller: Caller<'_, StoreState>, ptr: i32| callbacks::host_response_func(caller, ptr),
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_RESPONSE_LEN_FN,
+        |caller: Caller<'_, StoreState>| -> i32 { callbacks::host_response_len_func(caller) },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::GUEST_RESPONSE_FN,
+        |caller: Caller<'_, StoreState>, ptr: i32, len: i32| {
+            callbacks::guest_response_func(caller, ptr, len)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::GUEST_ERROR_FN,
+        |caller: Caller<'_, StoreState>, ptr: i32, len: i32| {
+            callbacks::guest_error_func(caller, ptr, len)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_ERROR_FN,
+        |caller: Caller<'_, StoreState>, ptr: i32| callbacks::host_error_func(caller, ptr),
+    )?;
+    linker.func_wrap(
+        HOST_NAMESPACE,
+        WapcFunctions::HOST_ERROR_LEN_FN,
+        |caller: Caller<'_, StoreState>| -> i32 { callbacks::host_error_len_func(caller) },
+    )?;
+    Ok(())
+}
+
+// Called once per call, right after instantiation. Looks up the `Func`
+// corresponding to the `__guest_call` export.
+fn guest_call_fn(
+    store: &mut Store<StoreState>,
+    instance: &Instance,
+) -> Result<Func, Box<dyn Error>> {
+    instance
+        .get_func(&mut *store, WapcFunctions::GUEST_CALL)
+        .ok_or_else(|| "Guest module did not export __guest_call function!".into())
 }
 
-// Called once, then the result is cached. This returns a `Func` that corresponds
-// to the `__guest_call` export
-fn guest_call_fn(instance: &Instance) -> Result<Func, Box<dyn Error>> {
-    if let Some(func) = instance.get_func(WapcFunctions::GUEST_CALL) {
-        Ok(func)
-    } else {
-        Err("Guest module did not export __guest_call function!".into())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_host() -> Arc<ModuleState> {
+        Arc::new(ModuleState::new(Box::new(
+            |_id, _bd, _ns, _op, _payload: &[u8]| Ok(vec![]),
+        )))
+    }
+
+    fn loop_module() -> Vec<u8> {
+        wat::parse_str(
+            r#"(module
+                (func (export "__guest_call") (param i32 i32) (result i32)
+                    (loop $top
+                        br $top)
+                    i32.const 0))"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn out_of_fuel_is_reported() {
+        let buf = loop_module();
+        let mut provider = WasmtimeEngineProviderBuilder::new()
+            .with_fuel(10_000)
+            .build(&buf)
+            .unwrap();
+        provider.init(test_host()).unwrap();
+
+        let err = provider.call(0, 0).unwrap_err();
+        let guest_err = err
+            .downcast_ref::<GuestCallError>()
+            .expect("call should fail with a GuestCallError");
+        assert!(matches!(guest_err, GuestCallError::OutOfFuel));
+    }
+
+    #[test]
+    fn timeout_is_reported() {
+        let buf = loop_module();
+        let mut provider = WasmtimeEngineProviderBuilder::new()
+            .with_timeout(Duration::from_millis(50))
+            .build(&buf)
+            .unwrap();
+        provider.init(test_host()).unwrap();
+
+        let err = provider.call(0, 0).unwrap_err();
+        let guest_err = err
+            .downcast_ref::<GuestCallError>()
+            .expect("call should fail with a GuestCallError");
+        assert!(matches!(guest_err, GuestCallError::Timeout));
+    }
+
+    #[test]
+    fn wasi_import_requires_wasi_to_be_enabled() {
+        let buf = wat::parse_str(
+            r#"(module
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (func (export "__guest_call") (param i32 i32) (result i32) i32.const 0))"#,
+        )
+        .unwrap();
+
+        let mut without_wasi = WasmtimeEngineProvider::new(&buf).unwrap();
+        assert!(without_wasi.init(test_host()).is_err());
+
+        let mut with_wasi = WasmtimeEngineProviderBuilder::new()
+            .enable_wasi(WasiCtxBuilder::new())
+            .build(&buf)
+            .unwrap();
+        with_wasi.init(test_host()).unwrap();
+        assert_eq!(with_wasi.call(0, 0).unwrap(), 0);
     }
 }